@@ -1,13 +1,112 @@
 use std::path::Path;
+use std::thread::sleep;
+use std::time::Duration;
 
 /*
  * Re-export the SQLite crate we use for convenience:
  */
 pub use rusqlite;
 
+use std::cell::RefCell;
+
 use anyhow::{bail, Context, Result};
+use rusqlite::backup::{Backup, StepResult};
 use rusqlite::{Connection, OpenFlags, TransactionBehavior};
-use slog::{info, Logger};
+use slog::{debug, info, warn, Logger};
+
+/*
+ * How long, by default, we are willing to have SQLite retry in the busy
+ * handler before giving up and returning SQLITE_BUSY to the caller.
+ */
+const DEFAULT_BUSY_TIMEOUT_MS: u32 = 5_000;
+
+/*
+ * Default capacity of the connection's prepared statement LRU cache; see
+ * statement_cache_capacity() below.
+ */
+const DEFAULT_STATEMENT_CACHE_CAPACITY: usize = 16;
+
+type Registration = Box<dyn FnOnce(&Connection) -> rusqlite::Result<()>>;
+
+/*
+ * SQLite's trace and profile hooks are plain function pointers with no
+ * captured state, so we stash the logger (and profiling threshold) that the
+ * callbacks on this thread should use here.  Because there is only one slot
+ * per thread, only one SqliteSetup-opened, trace/profile-enabled connection
+ * may be live on a given thread at a time; TRACED_ACTIVE guards against a
+ * second such connection silently redirecting the first one's callbacks to
+ * the wrong logger.  Call clear_trace_state() after dropping a traced
+ * connection to open another one on the same thread.
+ *
+ * This state travels with the thread that called open(), not with the
+ * returned Connection, which is Send.  If a trace/profile-enabled
+ * connection is moved to, or otherwise used from, a different thread, the
+ * trace/profile callbacks will find no logger registered there: see
+ * warn_no_trace_log() below, which reports this once per offending thread
+ * rather than silently dropping the output.
+ */
+thread_local! {
+    static TRACE_LOG: RefCell<Option<Logger>> = RefCell::new(None);
+    static PROFILE_THRESHOLD_NS: RefCell<u64> = RefCell::new(0);
+    static TRACED_ACTIVE: std::cell::Cell<bool> = std::cell::Cell::new(false);
+    static WARNED_NO_TRACE_LOG: std::cell::Cell<bool> = std::cell::Cell::new(false);
+}
+
+/**
+ * Clear the thread-local state used by `SqliteSetup::trace()` and
+ * `SqliteSetup::profile_threshold()`, allowing another trace/profile-enabled
+ * connection to be opened on this thread.  Call this only after the
+ * previously opened traced connection has been dropped: `SqliteSetup` has
+ * no way to detect that automatically, because rusqlite's trace/profile
+ * hooks are plain function pointers with no captured state.  This state is
+ * per-thread, not per-connection, so a traced connection must stay on the
+ * thread that opened it or its log output will stop (with a one-time warning
+ * on stderr).
+ */
+pub fn clear_trace_state() {
+    TRACED_ACTIVE.with(|a| a.set(false));
+    TRACE_LOG.with(|l| *l.borrow_mut() = None);
+}
+
+/*
+ * Called when a trace/profile callback fires on a thread with no
+ * registered logger, i.e. the connection was moved to, or used from, a
+ * different thread than the one that called SqliteSetup::open().  Since
+ * there is nowhere to log through on this thread, fall back to stderr so
+ * the silent loss of query observability is at least visible; report it
+ * only once per thread to avoid spamming.
+ */
+fn warn_no_trace_log() {
+    WARNED_NO_TRACE_LOG.with(|warned| {
+        if !warned.replace(true) {
+            eprintln!(
+                "jmclib::sqlite: trace/profile callback fired on a thread \
+                 with no registered logger; the connection was probably \
+                 opened on, or moved from, a different thread.  Further \
+                 trace/profile events on this thread will not be logged."
+            );
+        }
+    });
+}
+
+fn trace_callback(sql: &str) {
+    TRACE_LOG.with(|log| match log.borrow().as_ref() {
+        Some(log) => debug!(log, "sql trace"; "sql" => sql),
+        None => warn_no_trace_log(),
+    });
+}
+
+fn profile_callback(sql: &str, elapsed: Duration) {
+    let nanos = elapsed.as_nanos() as u64;
+    let threshold = PROFILE_THRESHOLD_NS.with(|t| *t.borrow());
+    if nanos <= threshold {
+        return;
+    }
+    TRACE_LOG.with(|log| match log.borrow().as_ref() {
+        Some(log) => warn!(log, "slow statement"; "sql" => sql, "nanos" => nanos),
+        None => warn_no_trace_log(),
+    });
+}
 
 pub struct SqliteSetup {
     log: Option<Logger>,
@@ -15,6 +114,11 @@ pub struct SqliteSetup {
     cache_kb: Option<u32>,
     create: bool,
     check_integrity: bool,
+    busy_timeout_ms: u32,
+    registrations: Vec<Registration>,
+    trace: bool,
+    profile_threshold: Option<Duration>,
+    statement_cache_capacity: usize,
 }
 
 impl SqliteSetup {
@@ -25,6 +129,11 @@ impl SqliteSetup {
             cache_kb: None,
             create: false,
             check_integrity: true,
+            busy_timeout_ms: DEFAULT_BUSY_TIMEOUT_MS,
+            registrations: Vec::new(),
+            trace: false,
+            profile_threshold: None,
+            statement_cache_capacity: DEFAULT_STATEMENT_CACHE_CAPACITY,
         }
     }
 
@@ -53,7 +162,104 @@ impl SqliteSetup {
         self
     }
 
-    pub fn open<P: AsRef<Path>>(&self, path: P) -> Result<Connection> {
+    /**
+     * Set the maximum time, in milliseconds, that SQLite will spend
+     * retrying when it finds the database locked by another connection
+     * before giving up with SQLITE_BUSY.  Without this, concurrent
+     * access to a shared WAL database can fail immediately under
+     * ordinary contention.
+     */
+    pub fn busy_timeout_ms(&mut self, ms: u32) -> &mut Self {
+        self.busy_timeout_ms = ms;
+        self
+    }
+
+    /**
+     * Register a scalar SQL function that will be installed on the
+     * connection before the schema migration runs, so that it is
+     * available to use in, e.g., indexes and CHECK constraints.
+     */
+    pub fn scalar_function<F, T>(
+        &mut self,
+        name: &'static str,
+        n_arg: i32,
+        flags: rusqlite::functions::FunctionFlags,
+        x_func: F,
+    ) -> &mut Self
+    where
+        F: FnMut(&rusqlite::functions::Context) -> rusqlite::Result<T>
+            + Send
+            + 'static,
+        T: rusqlite::ToSql,
+    {
+        self.registrations.push(Box::new(move |c| {
+            c.create_scalar_function(name, n_arg, flags, x_func)
+        }));
+        self
+    }
+
+    /**
+     * Register a custom collation that will be installed on the connection
+     * before the schema migration runs, so that it is available to use in,
+     * e.g., indexes and ORDER BY clauses.
+     */
+    pub fn collation<F>(&mut self, name: &'static str, x_compare: F) -> &mut Self
+    where
+        F: Fn(&str, &str) -> std::cmp::Ordering + Send + 'static,
+    {
+        self.registrations
+            .push(Box::new(move |c| c.create_collation(name, x_compare)));
+        self
+    }
+
+    /**
+     * Log every expanded SQL statement as it executes, at debug level,
+     * through the `Logger` passed to `log()`.
+     *
+     * SAFETY NOTE: SQLite's trace callback is a plain function pointer
+     * with no captured state, so this crate keeps the active logger in
+     * thread-local storage, keyed by the thread that called `open()`, not
+     * by the connection itself.  Only one trace/profile-enabled connection
+     * may be open on a given thread at a time; `open()` will fail if a
+     * second one is requested before `clear_trace_state()` is called for
+     * the first.  Because `Connection` is `Send`, it is also possible to
+     * move a trace/profile-enabled connection to another thread (or use
+     * it from a thread pool) after `open()` returns: logging stops
+     * working on that other thread, since there is no registered
+     * logger there (a one-time warning is printed to stderr when this
+     * happens).  Keep traced connections pinned to the thread that opened
+     * them.
+     */
+    pub fn trace(&mut self, trace: bool) -> &mut Self {
+        self.trace = trace;
+        self
+    }
+
+    /**
+     * Log, at warn level, any statement whose execution takes longer than
+     * the given duration.  Useful for finding slow migrations and hot
+     * paths without instrumenting call sites individually.
+     *
+     * SAFETY NOTE: see the caveat on `trace()` above; this shares the
+     * same thread-local, one-connection-at-a-time restriction.
+     */
+    pub fn profile_threshold(&mut self, threshold: Duration) -> &mut Self {
+        self.profile_threshold = Some(threshold);
+        self
+    }
+
+    /**
+     * Set the capacity of the connection's LRU cache of prepared
+     * statements, used by `Connection::prepare_cached()` to avoid
+     * re-parsing and re-planning the same SQL on every call in hot loops.
+     * Pass 0 to disable the cache entirely.
+     */
+    pub fn statement_cache_capacity(&mut self, capacity: usize) -> &mut Self {
+        self.statement_cache_capacity = capacity;
+        self
+    }
+
+    pub fn open<P: AsRef<Path>>(&mut self, path: P) -> Result<Connection> {
         let path = path.as_ref();
         let log = self
             .log
@@ -70,6 +276,60 @@ impl SqliteSetup {
         let mut c = Connection::open_with_flags(path, flags)
             .context("opening database")?;
 
+        /*
+         * Install a busy handler so that contention for the write lock on
+         * a shared WAL database produces bounded retry-with-backoff rather
+         * than an immediate SQLITE_BUSY error.  A configured timeout of
+         * zero means "fail immediately on lock contention", so in that
+         * case we leave no handler installed at all rather than retrying
+         * even once.
+         */
+        let busy_timeout_ms = u64::from(self.busy_timeout_ms);
+        if busy_timeout_ms > 0 {
+            let busy_log = log.clone();
+            c.busy_handler(Some(move |count| {
+                if count == 0 {
+                    return true;
+                }
+                debug!(busy_log, "database busy, retry {}", count);
+                std::thread::sleep(Duration::from_millis(20));
+                (count as u64) * 20 < busy_timeout_ms
+            }))
+            .context("install busy handler")?;
+        } else {
+            /*
+             * rusqlite installs a default busy timeout when it opens the
+             * connection; clear it.
+             */
+            c.busy_timeout(Duration::ZERO)
+                .context("clear busy handler")?;
+        }
+
+        /*
+         * SQLite's trace/profile callbacks are plain function pointers
+         * with no captured state, so stash the logger and threshold they
+         * should use for this thread before installing them.
+         */
+        if self.trace || self.profile_threshold.is_some() {
+            let already_active = TRACED_ACTIVE.with(|a| a.replace(true));
+            if already_active {
+                bail!(
+                    "a trace/profile-enabled connection is already open on \
+                     this thread; only one is supported at a time (call \
+                     sqlite::clear_trace_state() after dropping it)"
+                );
+            }
+            TRACE_LOG.with(|l| *l.borrow_mut() = Some(log.clone()));
+        }
+        if self.trace {
+            c.trace(Some(trace_callback));
+        }
+        if let Some(threshold) = self.profile_threshold {
+            PROFILE_THRESHOLD_NS
+                .with(|t| *t.borrow_mut() = threshold.as_nanos() as u64);
+            c.profile(Some(profile_callback));
+        }
+
         if self.check_integrity {
             let integrity: String = c
                 .query_row("PRAGMA integrity_check", [], |row| Ok(row.get(0)?))
@@ -107,6 +367,16 @@ impl SqliteSetup {
                 .context("set cache size")?;
         }
 
+        /*
+         * Install any user-defined scalar functions and collations before
+         * running the schema migration below, so that schema DDL (e.g. an
+         * index or CHECK constraint referencing one of them) can actually
+         * use what was registered.
+         */
+        for reg in self.registrations.drain(..) {
+            reg(&c).context("register function or collation")?;
+        }
+
         if let Some(schema) = self.schema.as_deref() {
             /*
              * Take the schema file and split it on the special comments we use
@@ -200,6 +470,207 @@ impl SqliteSetup {
             }
         }
 
+        c.set_prepared_statement_cache_capacity(self.statement_cache_capacity);
+
         Ok(c)
     }
 }
+
+/**
+ * Drive an online hot backup of a live database, using SQLite's backup API so
+ * that the source connection remains usable for reads and writes while the
+ * copy is in progress.
+ */
+pub struct SqliteBackup {
+    pages_per_step: i32,
+    sleep: Duration,
+}
+
+impl SqliteBackup {
+    pub fn new() -> SqliteBackup {
+        SqliteBackup {
+            pages_per_step: 100,
+            sleep: Duration::from_millis(250),
+        }
+    }
+
+    pub fn pages_per_step(&mut self, pages: i32) -> &mut Self {
+        self.pages_per_step = pages;
+        self
+    }
+
+    pub fn sleep(&mut self, sleep: Duration) -> &mut Self {
+        self.sleep = sleep;
+        self
+    }
+
+    /**
+     * Copy the contents of "from" into a fresh database file at "to",
+     * stepping the backup a bounded number of pages at a time so that the
+     * source database is not starved of the write lock for long periods.
+     */
+    pub fn run<P: AsRef<Path>>(
+        &self,
+        log: &Logger,
+        from: &Connection,
+        to: P,
+    ) -> Result<()> {
+        let to = to.as_ref();
+        info!(log, "starting online backup of database to {:?}", to);
+
+        let mut dst =
+            Connection::open(to).context("open backup destination")?;
+
+        {
+            let backup =
+                Backup::new(from, &mut dst).context("create backup handle")?;
+
+            loop {
+                let r = backup.step(self.pages_per_step);
+
+                match r {
+                    Ok(StepResult::Done) => break,
+                    Ok(StepResult::More) => {}
+                    Ok(StepResult::Busy) | Ok(StepResult::Locked) => {
+                        /*
+                         * The source database is busy or locked; this is
+                         * expected under concurrent write load, so just
+                         * retry on the next step.
+                         */
+                    }
+                    Err(e) => return Err(e).context("backup step"),
+                }
+
+                let p = backup.progress();
+                info!(
+                    log,
+                    "backup progress: {} of {} pages remaining",
+                    p.remaining,
+                    p.pagecount,
+                );
+
+                sleep(self.sleep);
+            }
+        }
+
+        dst.execute_batch("PRAGMA wal_checkpoint(TRUNCATE)")
+            .context("checkpoint backup destination")?;
+
+        info!(log, "backup complete");
+        Ok(())
+    }
+}
+
+use rusqlite::session::{ConflictAction, ConflictType, Session};
+
+/**
+ * Record a changeset describing exactly what a transaction modified, so
+ * that it can be shipped to and replayed against a remote copy of the
+ * database instead of re-sending the whole file.
+ */
+pub struct ChangesetSession<'conn> {
+    conn: &'conn Connection,
+    session: Session<'conn>,
+}
+
+impl<'conn> ChangesetSession<'conn> {
+    /**
+     * Attach a new session to the given connection.  If "tables" is
+     * empty, every table in the database is tracked; otherwise, only the
+     * named tables are tracked.
+     */
+    pub fn new(conn: &'conn Connection, tables: &[&str]) -> Result<Self> {
+        let mut session = Session::new(conn).context("create session")?;
+
+        if tables.is_empty() {
+            session.attach(None).context("attach session")?;
+        } else {
+            for table in tables {
+                session.attach(Some(table)).context("attach session table")?;
+            }
+        }
+
+        Ok(ChangesetSession { conn, session })
+    }
+
+    /**
+     * Run "f" against the connection this session is attached to, then
+     * return both its result and a changeset blob describing every change
+     * the attached tables have seen since this session was created.  The
+     * session is not reset between calls, so a later capture() also
+     * includes the changes made by earlier ones.
+     */
+    pub fn capture<T>(
+        &mut self,
+        f: impl FnOnce(&Connection) -> Result<T>,
+    ) -> Result<(T, Vec<u8>)> {
+        let r = f(self.conn)?;
+
+        let mut changeset = Vec::new();
+        self.session
+            .changeset_strm(&mut changeset)
+            .context("generate changeset")?;
+
+        Ok((r, changeset))
+    }
+}
+
+/**
+ * Apply a changeset, as produced by [`ChangesetSession::capture`], to
+ * another database, resolving any conflicts with the given callback.
+ */
+pub fn apply_changeset(
+    conn: &Connection,
+    changeset: &[u8],
+    mut on_conflict: impl FnMut(ConflictType) -> ConflictAction,
+) -> Result<()> {
+    conn.apply_strm(
+        &mut &changeset[..],
+        None::<fn(&str) -> bool>,
+        |conflict_type, _iter| on_conflict(conflict_type),
+    )
+    .context("apply changeset")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memdb() -> Connection {
+        let c = Connection::open_in_memory().unwrap();
+        c.execute_batch("CREATE TABLE widget (id INTEGER PRIMARY KEY, name TEXT)")
+            .unwrap();
+        c
+    }
+
+    #[test]
+    fn capture_and_apply_changeset() {
+        let src = memdb();
+        let dst = memdb();
+
+        let mut session = ChangesetSession::new(&src, &[]).unwrap();
+        let (_, changeset) = session
+            .capture(|c| {
+                c.execute(
+                    "INSERT INTO widget (id, name) VALUES (1, 'sprocket')",
+                    [],
+                )?;
+                Ok(())
+            })
+            .unwrap();
+
+        assert!(!changeset.is_empty());
+
+        apply_changeset(&dst, &changeset, |_conflict_type| ConflictAction::Abort)
+            .unwrap();
+
+        let name: String = dst
+            .query_row("SELECT name FROM widget WHERE id = 1", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(name, "sprocket");
+    }
+}
@@ -7,6 +7,27 @@ use serde::{Serialize, Deserialize};
 
 use tempfile::NamedTempFile;
 
+/*
+ * Files whose name ends in one of these suffixes are transparently
+ * compressed on write and decompressed on read, so that large config and
+ * state files can be stored compactly on disk without changing call
+ * sites.
+ */
+#[derive(Clone, Copy, PartialEq)]
+enum Codec {
+    None,
+    Xz,
+    Zstd,
+}
+
+fn codec_for(p: &Path) -> Codec {
+    match p.extension().and_then(|e| e.to_str()) {
+        Some("xz") => Codec::Xz,
+        Some("zst") => Codec::Zstd,
+        _ => Codec::None,
+    }
+}
+
 type Result<T> = std::result::Result<T, TomlError>;
 
 #[derive(Debug)]
@@ -63,10 +84,26 @@ where
         }
         Ok(f) => f,
     };
-    let mut r = BufReader::new(f);
+    let r = BufReader::new(f);
     let mut buf = Vec::<u8>::new();
 
-    r.read_to_end(&mut buf).context(p)?;
+    match codec_for(p) {
+        Codec::None => {
+            let mut r = r;
+            r.read_to_end(&mut buf).context(p)?;
+        }
+        Codec::Xz => {
+            xz2::bufread::XzDecoder::new(r)
+                .read_to_end(&mut buf)
+                .context(p)?;
+        }
+        Codec::Zstd => {
+            zstd::stream::read::Decoder::new(r)
+                .context(p)?
+                .read_to_end(&mut buf)
+                .context(p)?;
+        }
+    }
 
     Ok(Some(toml::from_slice(&buf).context(p)?))
 }
@@ -90,9 +127,45 @@ where
     let tf = NamedTempFile::new_in(dir).context(p)?;
 
     {
-        let mut w = BufWriter::new(tf.as_file());
-        w.write_all(&o).context(p)?;
-        w.flush().context(p)?;
+        let w = BufWriter::new(tf.as_file());
+        match codec_for(p) {
+            Codec::None => {
+                let mut w = w;
+                w.write_all(&o).context(p)?;
+                w.flush().context(p)?;
+            }
+            Codec::Xz => {
+                /*
+                 * Use a larger dictionary than the default preset to get a
+                 * better ratio on repetitive TOML.
+                 */
+                let mut opts = xz2::stream::LzmaOptions::new_preset(9)
+                    .context(p)?;
+                opts.dict_size(64 * 1024 * 1024);
+                let mut filters = xz2::stream::Filters::new();
+                filters.lzma2(&opts);
+                let stream = xz2::stream::Stream::new_stream_encoder(
+                    &filters,
+                    xz2::stream::Check::Crc64,
+                )
+                .context(p)?;
+                let mut w = xz2::write::XzEncoder::new_stream(w, stream);
+                w.write_all(&o).context(p)?;
+                w.finish().context(p)?.flush().context(p)?;
+            }
+            Codec::Zstd => {
+                /*
+                 * Don't use auto_finish() here: its Drop impl discards any
+                 * error from writing the zstd epilogue, which could leave
+                 * us fsync-ing and atomically installing a truncated file.
+                 * Call finish() explicitly so a failure is surfaced.
+                 */
+                let mut w = zstd::stream::write::Encoder::new(w, 0)
+                    .context(p)?;
+                w.write_all(&o).context(p)?;
+                w.finish().context(p)?.flush().context(p)?;
+            }
+        }
     }
 
     #[cfg(unix)]
@@ -117,3 +190,44 @@ where
     tf.persist(p).context(p)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Stuff {
+        name: String,
+        values: Vec<u32>,
+    }
+
+    fn roundtrip(suffix: &str) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(format!("stuff.toml{suffix}"));
+
+        let before = Stuff {
+            name: "example".into(),
+            values: vec![1, 2, 3],
+        };
+
+        write_file(&path, &before).unwrap();
+        let after: Stuff = read_file(&path).unwrap().unwrap();
+
+        assert_eq!(before, after);
+    }
+
+    #[test]
+    fn roundtrip_plain() {
+        roundtrip("");
+    }
+
+    #[test]
+    fn roundtrip_xz() {
+        roundtrip(".xz");
+    }
+
+    #[test]
+    fn roundtrip_zstd() {
+        roundtrip(".zst");
+    }
+}